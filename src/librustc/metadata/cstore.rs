@@ -48,6 +48,14 @@ pub enum LinkagePreference {
     RequireStatic,
 }
 
+// Why `get_used_crates` can refuse to produce a link line.
+pub enum CrateLinkError {
+    // A dependency cycle was found; the chain of crates that forms it.
+    Cycle(Vec<ast::CrateNum>),
+    // Two or more incompatible builds of the same crate were loaded.
+    Conflict(Vec<(CrateId, Vec<(ast::CrateNum, Svh)>)>),
+}
+
 #[deriving(Eq, FromPrimitive)]
 pub enum NativeLibaryKind {
     NativeStatic,    // native static library (.a archive)
@@ -150,23 +158,31 @@ impl CStore {
     //
     // In order to get this left-to-right dependency ordering, we perform a
     // topological sort of all crates putting the leaves at the right-most
-    // positions.
+    // positions (see `toposort` below). Conflicting crate versions are
+    // refused up front for the same reason: a link line built from two
+    // incompatible SVHs of the same crate is just as broken as one built
+    // from a cyclic graph.
     pub fn get_used_crates(&self, prefer: LinkagePreference)
-                           -> Vec<(ast::CrateNum, Option<Path>)> {
-        let mut ordering = Vec::new();
-        fn visit(cstore: &CStore, cnum: ast::CrateNum,
-                 ordering: &mut Vec<ast::CrateNum>) {
-            if ordering.as_slice().contains(&cnum) { return }
-            let meta = cstore.get_crate_data(cnum);
-            for (_, &dep) in meta.cnum_map.borrow().get().iter() {
-                visit(cstore, dep, ordering);
-            }
-            ordering.push(cnum);
-        };
-        for (&num, _) in self.metas.borrow().get().iter() {
-            visit(self, num, &mut ordering);
+                           -> Result<Vec<(ast::CrateNum, Option<Path>)>,
+                                     CrateLinkError> {
+        let conflicts = self.find_crate_conflicts();
+        if !conflicts.is_empty() {
+            return Err(Conflict(conflicts))
+        }
+
+        let mut edges = HashMap::new();
+        let nodes: Vec<ast::CrateNum> = self.metas.borrow().get()
+            .keys().map(|&cnum| cnum).collect();
+        for &cnum in nodes.iter() {
+            let meta = self.get_crate_data(cnum);
+            let deps = meta.cnum_map.borrow().get()
+                .values().map(|&dep| dep).collect();
+            edges.insert(cnum, deps);
         }
-        ordering.as_mut_slice().reverse();
+        let ordering = match toposort(nodes.as_slice(), &edges) {
+            Ok(ordering) => ordering,
+            Err(cycle) => return Err(Cycle(cycle)),
+        };
         let ordering = ordering.as_slice();
         let used_crate_sources = self.used_crate_sources.borrow();
         let mut libs = used_crate_sources.get()
@@ -179,7 +195,7 @@ impl CStore {
         libs.sort_by(|&(a, _), &(b, _)| {
             ordering.position_elem(&a).cmp(&ordering.position_elem(&b))
         });
-        libs
+        Ok(libs)
     }
 
     pub fn add_used_library(&self, lib: ~str, kind: NativeLibaryKind) {
@@ -216,6 +232,98 @@ impl CStore {
         let extern_mod_crate_map = self.extern_mod_crate_map.borrow();
         extern_mod_crate_map.get().find(&emod_id).map(|x| *x)
     }
+
+    // Groups all loaded crates by name/path (ignoring `version`, so a
+    // diamond dependency that pulls in two different versions of the same
+    // logical crate still lands in one group) and returns every group in
+    // which more than one distinct `Svh` shows up.
+    pub fn find_crate_conflicts(&self)
+                                -> Vec<(CrateId, Vec<(ast::CrateNum, Svh)>)> {
+        let metas = self.metas.borrow();
+        let crates: Vec<(ast::CrateNum, CrateId, Svh)> = metas.get().iter()
+            .map(|(&cnum, _)| (cnum, self.get_crate_id(cnum), self.get_crate_hash(cnum)))
+            .collect();
+        group_conflicts(crates.as_slice())
+    }
+}
+
+// The pure grouping/filtering logic behind `find_crate_conflicts`, pulled
+// out into a free function so it can be unit-tested against made-up
+// crate ids and hashes instead of real decoded metadata.
+fn group_conflicts(crates: &[(ast::CrateNum, CrateId, Svh)])
+                   -> Vec<(CrateId, Vec<(ast::CrateNum, Svh)>)> {
+    let mut grouped: Vec<(CrateId, Vec<(ast::CrateNum, Svh)>)> = Vec::new();
+    for &(cnum, ref id, ref hash) in crates.iter() {
+        let pos = grouped.iter().position(|&(ref gid, _)| {
+            gid.name == id.name && gid.path == id.path
+        });
+        match pos {
+            Some(i) => match grouped.mut_iter().nth(i) {
+                Some(&mut (_, ref mut versions)) => versions.push((cnum, hash.clone())),
+                None => unreachable!(),
+            },
+            None => grouped.push((id.clone(), vec!((cnum, hash.clone())))),
+        }
+    }
+    grouped.move_iter().filter(|&(_, ref versions)| {
+        let mut hashes = versions.iter().map(|&(_, ref hash)| hash.clone());
+        let first = hashes.next();
+        hashes.any(|h| Some(h) != first)
+    }).collect()
+}
+
+// The pure topological sort behind `get_used_crates`: `stack` holds the
+// crates currently being visited (the "gray" set); a crate already pushed
+// onto `ordering` is "black" and done. If `visit` ever finds a dependency
+// that's gray rather than black or unvisited, that's a back edge in the
+// dependency graph, i.e. a cycle, and the offending chain of crates is
+// returned so the driver can report it instead of producing a silently
+// order-dependent (or simply wrong) link line. Pulled out into a free
+// function over a plain adjacency map so it can be unit-tested without
+// needing a real `CStore`.
+fn toposort(nodes: &[ast::CrateNum], edges: &HashMap<ast::CrateNum, Vec<ast::CrateNum>>)
+           -> Result<Vec<ast::CrateNum>, Vec<ast::CrateNum>> {
+    let mut ordering = Vec::new();
+    let mut stack = Vec::new();
+    fn visit(cnum: ast::CrateNum,
+             edges: &HashMap<ast::CrateNum, Vec<ast::CrateNum>>,
+             stack: &mut Vec<ast::CrateNum>,
+             ordering: &mut Vec<ast::CrateNum>)
+             -> Option<Vec<ast::CrateNum>> {
+        if ordering.as_slice().contains(&cnum) { return None }
+        match stack.as_slice().position_elem(&cnum) {
+            Some(pos) => {
+                // `cnum` is already on the stack: we've found a cycle.
+                let mut cycle = stack.slice_from(pos).to_vec();
+                cycle.push(cnum);
+                return Some(cycle)
+            }
+            None => {}
+        }
+        stack.push(cnum);
+        match edges.find(&cnum) {
+            Some(deps) => {
+                for &dep in deps.iter() {
+                    match visit(dep, edges, stack, ordering) {
+                        Some(cycle) => return Some(cycle),
+                        None => {}
+                    }
+                }
+            }
+            None => {}
+        }
+        stack.pop();
+        ordering.push(cnum);
+        None
+    }
+    for &num in nodes.iter() {
+        match visit(num, edges, &mut stack, &mut ordering) {
+            Some(cycle) => return Err(cycle),
+            None => {}
+        }
+    }
+    ordering.as_mut_slice().reverse();
+    Ok(ordering)
 }
 
 impl crate_metadata {
@@ -230,3 +338,73 @@ impl MetadataBlob {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{group_conflicts, toposort};
+    use back::svh::Svh;
+    use collections::HashMap;
+    use syntax::ast;
+    use syntax::crateid::CrateId;
+
+    fn crate_id(name: &str) -> CrateId {
+        CrateId { name: name.to_owned(), path: name.to_owned(), version: None }
+    }
+
+    #[test]
+    fn conflicting_hashes_for_the_same_name_and_path_are_reported() {
+        let crates = [
+            (1 as ast::CrateNum, crate_id("foo"), Svh::new("0000000000000000")),
+            (2 as ast::CrateNum, crate_id("foo"), Svh::new("1111111111111111")),
+        ];
+        let conflicts = group_conflicts(crates.as_slice());
+        assert_eq!(conflicts.len(), 1);
+        let (ref id, ref versions) = conflicts[0];
+        assert_eq!(id.name, "foo".to_owned());
+        assert_eq!(versions.len(), 2);
+    }
+
+    #[test]
+    fn matching_hashes_for_the_same_name_and_path_are_not_a_conflict() {
+        let hash = Svh::new("0000000000000000");
+        let crates = [
+            (1 as ast::CrateNum, crate_id("foo"), hash.clone()),
+            (2 as ast::CrateNum, crate_id("foo"), hash.clone()),
+        ];
+        assert!(group_conflicts(crates.as_slice()).is_empty());
+    }
+
+    #[test]
+    fn different_names_never_conflict_even_with_different_hashes() {
+        let crates = [
+            (1 as ast::CrateNum, crate_id("foo"), Svh::new("0000000000000000")),
+            (2 as ast::CrateNum, crate_id("bar"), Svh::new("1111111111111111")),
+        ];
+        assert!(group_conflicts(crates.as_slice()).is_empty());
+    }
+
+    #[test]
+    fn toposort_reports_the_cycle_chain() {
+        let mut edges = HashMap::new();
+        edges.insert(1 as ast::CrateNum, vec!(2 as ast::CrateNum));
+        edges.insert(2 as ast::CrateNum, vec!(3 as ast::CrateNum));
+        edges.insert(3 as ast::CrateNum, vec!(1 as ast::CrateNum));
+        let nodes = [1 as ast::CrateNum, 2, 3];
+        match toposort(nodes.as_slice(), &edges) {
+            Err(cycle) => assert_eq!(cycle, vec!(1 as ast::CrateNum, 2, 3, 1)),
+            Ok(_) => fail!("expected a cycle to be detected"),
+        }
+    }
+
+    #[test]
+    fn toposort_puts_dependents_before_their_leaves() {
+        let mut edges = HashMap::new();
+        edges.insert(1 as ast::CrateNum, vec!(2 as ast::CrateNum));
+        edges.insert(2 as ast::CrateNum, Vec::new());
+        let nodes = [1 as ast::CrateNum, 2];
+        match toposort(nodes.as_slice(), &edges) {
+            Ok(ordering) => assert_eq!(ordering, vec!(1 as ast::CrateNum, 2)),
+            Err(_) => fail!("did not expect a cycle"),
+        }
+    }
+}
@@ -24,19 +24,89 @@ use html::escape::Escape;
 
 use t = syntax::parse::token;
 
+/// The kind of item an identifier resolved to, used to pick its CSS class.
+pub enum TokenClass {
+    TypeClass,
+    TraitClass,
+    FnClass,
+    StaticClass,
+    EnumVariantClass,
+    LocalClass,
+}
+
+impl TokenClass {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            TypeClass => "type",
+            TraitClass => "trait",
+            FnClass => "fn",
+            StaticClass => "static",
+            EnumVariantClass => "enumval",
+            LocalClass => "local",
+        }
+    }
+}
+
 /// Highlights some source code, returning the HTML output.
-pub fn highlight(src: &str, class: Option<&str>) -> ~str {
+///
+/// `resolve`, if given, is consulted for each `IDENT` token's span before
+/// falling back to the spelling-based heuristics below. `line_numbers`
+/// wraps each line in an addressable `<span id='N' class='line'>` and
+/// emits a line-number gutter.
+pub fn highlight(src: &str, class: Option<&str>,
+                  resolve: Option<|Span| -> Option<TokenClass>|>,
+                  line_numbers: bool) -> ~str {
     let sess = parse::new_parse_sess();
     let fm = parse::string_to_filemap(&sess, src.to_owned(), ~"<stdin>");
+    let total_lines = src.chars().filter(|&c| c == '\n').count() + 1;
 
     let mut out = io::MemWriter::new();
     doit(&sess,
          lexer::new_string_reader(&sess.span_diagnostic, fm),
          class,
+         resolve,
+         line_numbers,
+         total_lines,
          &mut out).unwrap();
     str::from_utf8_lossy(out.unwrap()).into_owned()
 }
 
+// Writes `text` wrapped in a `<span class='{klass}'>` (if `klass` isn't
+// empty), closing and reopening both that span and the current line span
+// around any newlines in `text` so the two never end up improperly nested.
+// `outer` is the class of a span opened *outside* this call (e.g. the
+// `attribute` wrapper, which stays open across several tokens) that is
+// still open around `text`; it's closed and reopened alongside the line
+// span so the nesting never drifts. `line` is the 1-based number of the
+// line currently open.
+fn emit(out: &mut Writer, text: &str, klass: &str, outer: Option<&str>,
+        line_numbers: bool, line: &mut uint) -> io::IoResult<()> {
+    if !line_numbers || !text.contains("\n") {
+        return if klass == "" {
+            write!(out, "{}", Escape(text))
+        } else {
+            write!(out, "<span class='{}'>{}</span>", klass, Escape(text))
+        }
+    }
+
+    let parts: Vec<&str> = text.split('\n').collect();
+    for (i, part) in parts.iter().enumerate() {
+        if klass != "" { try!(write!(out, "<span class='{}'>", klass)); }
+        try!(write!(out, "{}", Escape(*part)));
+        if klass != "" { try!(write!(out, "</span>")); }
+        if i + 1 < parts.len() {
+            *line += 1;
+            match outer { Some(..) => try!(write!(out, "</span>")), None => {} }
+            try!(write!(out, "</span>\n<span id='{}' class='line'>", *line));
+            match outer {
+                Some(c) => try!(write!(out, "<span class='{}'>", c)),
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Exhausts the `lexer` writing the output into `out`.
 ///
 /// The general structure for this method is to iterate over each token,
@@ -45,10 +115,24 @@ pub fn highlight(src: &str, class: Option<&str>) -> ~str {
 /// not from the tokens themselves, in order to stay true to the original
 /// source.
 fn doit(sess: &parse::ParseSess, lexer: lexer::StringReader, class: Option<&str>,
+        mut resolve: Option<|Span| -> Option<TokenClass>|>,
+        line_numbers: bool, total_lines: uint,
         out: &mut Writer) -> io::IoResult<()> {
     use syntax::parse::lexer::Reader;
 
+    if line_numbers {
+        try!(write!(out, "<pre class='line-numbers'>"));
+        for i in range(1, total_lines + 1) {
+            try!(write!(out, "{}\n", i));
+        }
+        try!(write!(out, "</pre>\n"));
+    }
+
     try!(write!(out, "<pre class='rust {}'>\n", class.unwrap_or("")));
+    let mut line = 1u;
+    if line_numbers {
+        try!(write!(out, "<span id='1' class='line'>"));
+    }
     let mut last = BytePos(0);
     let mut is_attribute = false;
     let mut is_macro = false;
@@ -70,12 +154,9 @@ fn doit(sess: &parse::ParseSess, lexer: lexer::StringReader, class: Option<&str>
                 hi: test,
                 expn_info: None,
             }).unwrap();
-            if snip.contains("/") {
-                try!(write!(out, "<span class='comment'>{}</span>",
-                              Escape(snip)));
-            } else {
-                try!(write!(out, "{}", Escape(snip)));
-            }
+            let klass = if snip.contains("/") { "comment" } else { "" };
+            let outer = if is_attribute { Some("attribute") } else { None };
+            try!(emit(out, snip, klass, outer, line_numbers, &mut line));
         }
         last = next.sp.hi;
         if next.tok == t::EOF { break }
@@ -138,25 +219,40 @@ fn doit(sess: &parse::ParseSess, lexer: lexer::StringReader, class: Option<&str>
 
             // keywords are also included in the identifier set
             t::IDENT(ident, _is_mod_sep) => {
-                match t::get_ident(ident).get() {
-                    "ref" | "mut" => "kw-2",
-
-                    "self" => "self",
-                    "false" | "true" => "boolval",
-
-                    "Option" | "Result" => "prelude-ty",
-                    "Some" | "None" | "Ok" | "Err" => "prelude-val",
-
-                    _ if t::is_any_keyword(&next.tok) => "kw",
-                    _ => {
-                        if is_macro_nonterminal {
-                            is_macro_nonterminal = false;
-                            "macro-nonterminal"
-                        } else if lexer.peek().tok == t::NOT {
-                            is_macro = true;
-                            "macro"
-                        } else {
-                            "ident"
+                let resolved = match resolve {
+                    Some(ref mut resolve) => (*resolve)(next.sp),
+                    None => None,
+                };
+
+                // This needs to be cleared for every identifier, not just
+                // when the spelling-based fallback below ends up being
+                // consulted, or a resolver supplying a class for this
+                // token would leave stale state for whichever token comes
+                // next.
+                let was_macro_nonterminal = is_macro_nonterminal;
+                is_macro_nonterminal = false;
+
+                match resolved {
+                    Some(class) => class.as_str(),
+                    None => match t::get_ident(ident).get() {
+                        "ref" | "mut" => "kw-2",
+
+                        "self" => "self",
+                        "false" | "true" => "boolval",
+
+                        "Option" | "Result" => "prelude-ty",
+                        "Some" | "None" | "Ok" | "Err" => "prelude-val",
+
+                        _ if t::is_any_keyword(&next.tok) => "kw",
+                        _ => {
+                            if was_macro_nonterminal {
+                                "macro-nonterminal"
+                            } else if lexer.peek().tok == t::NOT {
+                                is_macro = true;
+                                "macro"
+                            } else {
+                                "ident"
+                            }
                         }
                     }
                 }
@@ -170,13 +266,76 @@ fn doit(sess: &parse::ParseSess, lexer: lexer::StringReader, class: Option<&str>
         // as mentioned above, use the original source code instead of
         // stringifying this token
         let snip = sess.span_diagnostic.cm.span_to_snippet(next.sp).unwrap();
-        if klass == "" {
-            try!(write!(out, "{}", Escape(snip)));
-        } else {
-            try!(write!(out, "<span class='{}'>{}</span>", klass,
-                          Escape(snip)));
-        }
+        let outer = if is_attribute { Some("attribute") } else { None };
+        try!(emit(out, snip, klass, outer, line_numbers, &mut line));
     }
 
+    if line_numbers {
+        try!(write!(out, "</span>"));
+    }
     write!(out, "</pre>\n")
 }
+
+#[cfg(test)]
+mod test {
+    use super::{emit, highlight, TokenClass, TypeClass};
+    use std::io;
+    use std::str;
+    use syntax::codemap::Span;
+
+    fn emitted(text: &str, klass: &str, outer: Option<&str>) -> ~str {
+        let mut out = io::MemWriter::new();
+        let mut line = 1u;
+        emit(&mut out, text, klass, outer, true, &mut line).unwrap();
+        str::from_utf8_lossy(out.unwrap()).into_owned()
+    }
+
+    #[test]
+    fn emit_closes_and_reopens_the_line_span_around_a_newline() {
+        let html = emitted("a\nb", "tok", None);
+        assert_eq!(html, "<span class='tok'>a</span></span>\n\
+                           <span id='2' class='line'><span class='tok'>b</span>".to_owned());
+    }
+
+    // The `outer` (attribute) span has to be closed and reopened alongside
+    // the line span, not just left dangling across the newline or closed
+    // in the wrong order.
+    #[test]
+    fn emit_closes_and_reopens_an_outer_span_around_a_newline() {
+        let html = emitted("a\nb", "tok", Some("attribute"));
+        assert_eq!(html, "<span class='tok'>a</span></span></span>\n\
+                           <span id='2' class='line'><span class='attribute'><span class='tok'>b</span>".to_owned());
+    }
+
+    // With no resolver, the spelling-based fallback (keywords, plain
+    // idents, and the prelude names) must be untouched.
+    #[test]
+    fn highlight_without_resolver_keeps_todays_classes() {
+        let html = highlight("fn main() { Some(1) }", None, None, false);
+        assert!(html.contains("<span class='kw'>fn</span>"));
+        assert!(html.contains("<span class='ident'>main</span>"));
+        assert!(html.contains("<span class='prelude-val'>Some</span>"));
+    }
+
+    // A resolver-classified identifier shouldn't leave `is_macro` set for
+    // the `!` that follows it.
+    #[test]
+    fn resolved_ident_followed_by_bang_does_not_leak_into_macro_class() {
+        let resolve: |Span| -> Option<TokenClass> = |_sp| Some(TypeClass);
+        let html = highlight("Foo!()", None, Some(resolve), false);
+        assert!(html.contains("<span class='type'>Foo</span>"));
+        assert!(html.contains("<span class='op'>!</span>"));
+        assert!(!html.contains("class='macro'"));
+    }
+
+    // End-to-end: the line-number gutter and the per-line span wrapper
+    // both have to show up in `highlight`'s actual output, not just in
+    // the lower-level `emit` helper exercised above.
+    #[test]
+    fn highlight_with_line_numbers_emits_gutter_and_line_spans() {
+        let html = highlight("a\nb", None, None, true);
+        assert!(html.contains("<pre class='line-numbers'>1\n2\n</pre>"));
+        assert!(html.contains("<span id='1' class='line'>"));
+        assert!(html.contains("<span id='2' class='line'>"));
+    }
+}